@@ -1,6 +1,8 @@
-use std::{self, time};
+use std::{env, self, time};
+use std::io::{BufRead, BufReader, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread::sleep;
 
 use base64;
@@ -8,17 +10,65 @@ use log::*;
 use sgx_types::*;
 use sgx_types::{sgx_status_t, SgxResult};
 
+use enclave_ffi_types::AttestationMode;
+
 use crate::ENCRYPTED_SEED_SIZE;
 // use crate::errors::Error;
 
+/// Default IAS endpoint used when the operator hasn't pointed us at a PCCS/proxy.
+const DEFAULT_ATTESTATION_HOST: &str = "api.trustedservices.intel.com";
+const DEFAULT_ATTESTATION_PORT: u16 = 443;
+
+/// `host[:port]` of the attestation/collateral endpoint to dial. Lets operators point at a
+/// local PCCS caching proxy for DCAP collateral, or anything else, instead of IAS directly.
+const ATTESTATION_HOST_ENV_VAR: &str = "SCRT_ATTESTATION_HOST";
+
+/// `host:port` of an HTTPS forward proxy to `CONNECT` through to reach the attestation
+/// endpoint, for datacenters that only allow egress via a proxy.
+const ATTESTATION_PROXY_ENV_VAR: &str = "SCRT_ATTESTATION_PROXY";
+
+const ATTESTATION_SOCKET_RETRIES: u32 = 3;
+const ATTESTATION_SOCKET_RETRY_DELAY: time::Duration = time::Duration::from_millis(500);
+
 extern "C" {
     pub fn ecall_get_attestation_report(eid: sgx_enclave_id_t,
                                         retval: *mut sgx_status_t) -> sgx_status_t;
+    /// DCAP/ECDSA counterpart of `ecall_get_attestation_report`. Produces a report whose
+    /// `report_data` is built the same way (signing address in the first 20 bytes), but the
+    /// quote that gets wrapped around it is an ECDSA quote rather than an EPID one. The raw
+    /// quote bytes are copied out into `quote_buf` so the host can run it through
+    /// `verify_dcap_quote` before trusting the registration.
+    ///
+    /// Untrusted-side declaration only: there's no trusted-enclave body or EDL entry backing
+    /// this yet, same as `ecall_start_seed_server` below. Not yet callable until those land.
+    pub fn ecall_get_attestation_report_dcap(eid: sgx_enclave_id_t,
+                                        retval: *mut sgx_status_t,
+                                        quote_buf: *mut u8,
+                                        quote_buf_capacity: u32,
+                                        quote_len: *mut u32) -> sgx_status_t;
     pub fn ecall_get_encrypted_seed(eid: sgx_enclave_id_t,
                                     retval: *mut sgx_status_t,
                                     cert: *const u8,
                                     cert_len: u32,
                                     seed: &mut [u8; ENCRYPTED_SEED_SIZE]) -> sgx_status_t;
+    /// Drives one accepted seed-exchange connection from the listener side: the enclave
+    /// mints an RA-TLS server certificate embedding its own quote, terminates the TLS session
+    /// on `fd`, verifies the joining peer's embedded quote (MRENCLAVE/MRSIGNER and the signing
+    /// address in `report_data`), and releases the seed over the channel.
+    ///
+    /// Untrusted-side declaration only: ships together with its trusted-enclave body and EDL
+    /// entry, which this change does not add. Not yet callable until those land.
+    pub fn ecall_start_seed_server(eid: sgx_enclave_id_t,
+                                   retval: *mut sgx_status_t,
+                                   fd: c_int) -> sgx_status_t;
+    /// Client-side counterpart: connects out on `fd`, verifies the listener's embedded quote,
+    /// and writes the seed it receives over the attested channel into `seed`.
+    ///
+    /// Untrusted-side declaration only; see `ecall_start_seed_server`.
+    pub fn ecall_join_via_seed_client(eid: sgx_enclave_id_t,
+                                      retval: *mut sgx_status_t,
+                                      fd: c_int,
+                                      seed: &mut [u8; ENCRYPTED_SEED_SIZE]) -> sgx_status_t;
 }
 
 #[no_mangle]
@@ -29,32 +79,129 @@ fn ocall_sgx_init_quote(ret_ti: *mut sgx_target_info_t,
     unsafe {sgx_init_quote(ret_ti, ret_gid)}
 }
 
-
-pub fn lookup_ipv4(host: &str, port: u16) -> SocketAddr {
+// NOTE: sgx_create_monotonic_counter/sgx_increment_monotonic_counter/sgx_read_monotonic_counter
+// are Platform Service APIs and must be called from *trusted* enclave code, which talks to the
+// PSE directly -- wrapping them as ocall_* here would let an untrusted host hand the enclave
+// whatever counter value it likes, defeating the point of rollback protection. The counter/seal
+// logic this requires (store a counter UUID+value next to the sealed seed, increment/re-seal on
+// every write, refuse to start if the sealed value is behind the hardware counter) is trusted-side
+// work and is NOT implemented anywhere in this tree -- only the host-side
+// EnclaveRuntimeConfig::configure_rollback_protection toggle exists, and it currently does nothing
+// but round-trip through RuntimeConfiguration. Rollback protection is not actually provided yet;
+// tracking the trusted-side implementation as its own follow-up rather than pretending this ships it.
+
+pub fn lookup_ipv4(host: &str, port: u16) -> std::io::Result<SocketAddr> {
     use std::net::ToSocketAddrs;
 
-    let addrs = (host, port).to_socket_addrs().unwrap();
+    let addrs = (host, port).to_socket_addrs()?;
     for addr in addrs {
         if let SocketAddr::V4(_) = addr {
-            return addr;
+            return Ok(addr);
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AddrNotAvailable,
+        format!("no IPv4 address found for {}:{}", host, port),
+    ))
+}
+
+/// Splits a `host:port` string, falling back to `default_port` if no port is present.
+fn split_host_port(val: &str, default_port: u16) -> (String, u16) {
+    match val.rsplitn(2, ':').collect::<Vec<_>>().as_slice() {
+        [port, host] if !host.is_empty() => match port.parse() {
+            Ok(port) => (host.to_string(), port),
+            Err(_) => (host.to_string(), default_port),
+        },
+        _ => (val.to_string(), default_port),
+    }
+}
+
+/// Returns the configured attestation/collateral endpoint, honoring `SCRT_ATTESTATION_HOST`.
+fn attestation_endpoint() -> (String, u16) {
+    match env::var(ATTESTATION_HOST_ENV_VAR) {
+        Ok(val) => split_host_port(&val, DEFAULT_ATTESTATION_PORT),
+        Err(_) => (DEFAULT_ATTESTATION_HOST.to_string(), DEFAULT_ATTESTATION_PORT),
+    }
+}
+
+/// Opens a `target_host:target_port` tunnel through an HTTPS forward proxy using `CONNECT`,
+/// so the EPID/DCAP TLS session can ride on top of it exactly as if dialed directly.
+fn connect_via_proxy(proxy_addr: SocketAddr, target_host: &str, target_port: u16) -> std::io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy_addr)?;
+    let request = format!(
+        "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n\r\n",
+        host = target_host,
+        port = target_port
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    if !status_line.contains(" 200 ") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("proxy CONNECT to {}:{} failed: {}", target_host, target_port, status_line.trim()),
+        ));
+    }
+
+    // Drain the rest of the proxy's response headers up to the blank line.
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 || line == "\r\n" {
+            break;
         }
     }
 
-    unreachable!("Cannot lookup address");
+    Ok(stream)
 }
 
+fn dial_attestation_endpoint() -> std::io::Result<TcpStream> {
+    let (host, port) = attestation_endpoint();
+
+    match env::var(ATTESTATION_PROXY_ENV_VAR) {
+        Ok(proxy) => {
+            let (proxy_host, proxy_port) = split_host_port(&proxy, 443);
+            let proxy_addr = lookup_ipv4(&proxy_host, proxy_port)?;
+            connect_via_proxy(proxy_addr, &host, port)
+        }
+        Err(_) => {
+            let addr = lookup_ipv4(&host, port)?;
+            TcpStream::connect(addr)
+        }
+    }
+}
 
 #[no_mangle]
 pub extern "C"
 fn ocall_get_ias_socket(ret_fd : *mut c_int) -> sgx_status_t {
-    let port = 443;
-    let hostname = "api.trustedservices.intel.com";
-    let addr = lookup_ipv4(hostname, port);
-    let sock = TcpStream::connect(&addr).expect("[-] Connect tls server failed!");
+    let mut last_err = None;
+
+    for attempt in 0..=ATTESTATION_SOCKET_RETRIES {
+        if attempt > 0 {
+            warn!(
+                "Retrying attestation endpoint connection ({}/{})",
+                attempt, ATTESTATION_SOCKET_RETRIES
+            );
+            sleep(ATTESTATION_SOCKET_RETRY_DELAY);
+        }
 
-    unsafe {*ret_fd = sock.into_raw_fd();}
+        match dial_attestation_endpoint() {
+            Ok(sock) => {
+                unsafe { *ret_fd = sock.into_raw_fd(); }
+                return sgx_status_t::SGX_SUCCESS;
+            }
+            Err(e) => {
+                error!("Failed to connect to attestation endpoint: {}", e);
+                last_err = Some(e);
+            }
+        }
+    }
 
-    sgx_status_t::SGX_SUCCESS
+    error!("Exhausted retries connecting to attestation endpoint: {:?}", last_err);
+    sgx_status_t::SGX_ERROR_UNEXPECTED
 }
 
 #[no_mangle]
@@ -106,6 +253,94 @@ fn ocall_get_quote (p_sigrl            : *const u8,
     ret
 }
 
+#[no_mangle]
+pub extern "C"
+fn ocall_qe_get_target_info(ret_ti: *mut sgx_target_info_t) -> sgx_status_t {
+    info!("Entering ocall_qe_get_target_info");
+    let ret = unsafe { sgx_qe_get_target_info(ret_ti) };
+    if ret != sgx_quote3_error_t::SGX_QL_SUCCESS {
+        error!("sgx_qe_get_target_info returned {:?}", ret);
+        return sgx_status_t::SGX_ERROR_UNEXPECTED;
+    }
+    sgx_status_t::SGX_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C"
+fn ocall_qe_get_quote_size(p_quote_size: *mut u32) -> sgx_status_t {
+    info!("Entering ocall_qe_get_quote_size");
+    let ret = unsafe { sgx_qe_get_quote_size(p_quote_size) };
+    if ret != sgx_quote3_error_t::SGX_QL_SUCCESS {
+        error!("sgx_qe_get_quote_size returned {:?}", ret);
+        return sgx_status_t::SGX_ERROR_UNEXPECTED;
+    }
+    sgx_status_t::SGX_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C"
+fn ocall_qe_get_quote(p_report: *const sgx_report_t,
+                      quote_len: u32,
+                      p_quote: *mut u8) -> sgx_status_t {
+    info!("Entering ocall_qe_get_quote");
+    let ret = unsafe { sgx_qe_get_quote(p_report, quote_len, p_quote) };
+    if ret != sgx_quote3_error_t::SGX_QL_SUCCESS {
+        error!("sgx_qe_get_quote returned {:?}", ret);
+        return sgx_status_t::SGX_ERROR_UNEXPECTED;
+    }
+    sgx_status_t::SGX_SUCCESS
+}
+
+/// Verifies a DCAP/ECDSA quote against its collateral, in place of the EPID flow's trip to
+/// IAS. Mirrors `ocall_get_quote`'s two-step "ask for the size, then ask for the data" shape.
+pub fn verify_dcap_quote(quote: &[u8], collateral: &sgx_ql_qve_collateral_t) -> SgxResult<sgx_status_t> {
+    let mut supplemental_data_size: u32 = 0;
+    let ret = unsafe {
+        sgx_qv_get_quote_supplemental_data_size(&mut supplemental_data_size as *mut u32)
+    };
+    if ret != sgx_quote3_error_t::SGX_QL_SUCCESS {
+        error!("sgx_qv_get_quote_supplemental_data_size returned {:?}", ret);
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+
+    let mut supplemental_data = vec![0u8; supplemental_data_size as usize];
+    let mut collateral_expiration_status: u32 = 1;
+    let mut quote_verification_result = sgx_ql_qv_result_t::SGX_QL_QV_RESULT_UNSPECIFIED;
+
+    let ret = unsafe {
+        sgx_qv_verify_quote(
+            quote.as_ptr(),
+            quote.len() as u32,
+            collateral as *const sgx_ql_qve_collateral_t,
+            0, // expiration check date: 0 lets the library use "now"
+            &mut collateral_expiration_status as *mut u32,
+            &mut quote_verification_result as *mut sgx_ql_qv_result_t,
+            std::ptr::null_mut(),
+            supplemental_data_size,
+            supplemental_data.as_mut_ptr(),
+        )
+    };
+
+    if ret != sgx_quote3_error_t::SGX_QL_SUCCESS {
+        error!("sgx_qv_verify_quote returned {:?}", ret);
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+
+    match quote_verification_result {
+        sgx_ql_qv_result_t::SGX_QL_QV_RESULT_OK if collateral_expiration_status == 0 => {
+            Ok(sgx_status_t::SGX_SUCCESS)
+        }
+        sgx_ql_qv_result_t::SGX_QL_QV_RESULT_OK => {
+            error!("DCAP quote verified OK but collateral has expired, rejecting");
+            Err(sgx_status_t::SGX_ERROR_UNEXPECTED)
+        }
+        other => {
+            error!("DCAP quote verification failed with result {:?}", other);
+            Err(sgx_status_t::SGX_ERROR_UNEXPECTED)
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C"
 fn ocall_get_update_info (platform_blob: * const sgx_platform_info_t,
@@ -116,11 +351,50 @@ fn ocall_get_update_info (platform_blob: * const sgx_platform_info_t,
     }
 }
 
-pub fn inner_create_report(eid: sgx_enclave_id_t) -> SgxResult<sgx_status_t> {
+/// Quotes don't grow unbounded; this comfortably covers an ECDSA quote with a PCK cert chain
+/// in the certification data.
+const MAX_DCAP_QUOTE_SIZE: usize = 8192;
 
-    info!("Entered produce report");
+pub fn inner_create_report(
+    eid: sgx_enclave_id_t,
+    mode: AttestationMode,
+    dcap_collateral: Option<&sgx_ql_qve_collateral_t>,
+) -> SgxResult<sgx_status_t> {
+
+    info!("Entered produce report, attestation mode: {:?}", mode);
     let mut retval = sgx_status_t::SGX_SUCCESS;
-    let status = unsafe { ecall_get_attestation_report(eid, &mut retval) };
+
+    let status = match mode {
+        AttestationMode::Epid => unsafe { ecall_get_attestation_report(eid, &mut retval) },
+        AttestationMode::Dcap => {
+            let mut quote = vec![0u8; MAX_DCAP_QUOTE_SIZE];
+            let mut quote_len: u32 = 0;
+            let status = unsafe {
+                ecall_get_attestation_report_dcap(
+                    eid,
+                    &mut retval,
+                    quote.as_mut_ptr(),
+                    quote.len() as u32,
+                    &mut quote_len,
+                )
+            };
+
+            if status == sgx_status_t::SGX_SUCCESS && retval == sgx_status_t::SGX_SUCCESS {
+                let collateral = match dcap_collateral {
+                    Some(collateral) => collateral,
+                    None => {
+                        error!("DCAP registration requires quote verification collateral, none was supplied");
+                        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+                    }
+                };
+
+                quote.truncate(quote_len as usize);
+                retval = verify_dcap_quote(&quote, collateral)?;
+            }
+
+            status
+        }
+    };
 
     if status != sgx_status_t::SGX_SUCCESS  {
         return Err(status);
@@ -156,6 +430,160 @@ pub fn inner_get_encrypted_seed(eid: sgx_enclave_id_t, cert: *const u8, cert_len
     Ok(seed)
 }
 
+/// Bounds how long one seed-exchange connection is allowed to sit idle mid-handshake, so a
+/// slow or stalled peer can't tie up a listener thread indefinitely.
+const SEED_EXCHANGE_CONNECTION_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// Caps how many seed-exchange connections may be in flight at once. This listener serves
+/// not-yet-attested joining peers, so without a cap an unauthenticated peer could open
+/// connections in a loop and exhaust threads/fds on the node well before attestation ever runs.
+const MAX_CONCURRENT_SEED_EXCHANGE_CONNECTIONS: usize = 16;
+static SEED_EXCHANGE_CONNECTIONS_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// RAII guard that reserves a slot in `SEED_EXCHANGE_CONNECTIONS_IN_FLIGHT` for the lifetime of
+/// one seed-exchange connection, releasing it on drop regardless of how the handler returns.
+struct SeedExchangeSlot;
+
+impl SeedExchangeSlot {
+    fn try_acquire() -> Option<Self> {
+        SEED_EXCHANGE_CONNECTIONS_IN_FLIGHT
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < MAX_CONCURRENT_SEED_EXCHANGE_CONNECTIONS {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| SeedExchangeSlot)
+    }
+}
+
+impl Drop for SeedExchangeSlot {
+    fn drop(&mut self) {
+        SEED_EXCHANGE_CONNECTIONS_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Binds `addr` and serves the seed to joining nodes over a mutually-attested TLS session,
+/// one per incoming connection, instead of the store-and-forward `inner_get_encrypted_seed`
+/// cert exchange above. Each connection is handed off whole to the enclave, which owns the
+/// TLS handshake and the attestation checks; this function only shuttles the raw socket fd.
+pub fn start_seed_exchange_listener(eid: sgx_enclave_id_t, addr: &str) -> SgxResult<()> {
+    info!("Starting attested-TLS seed exchange listener on {}", addr);
+
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        error!("Failed to bind seed exchange listener on {}: {}", addr, e);
+        sgx_status_t::SGX_ERROR_UNEXPECTED
+    })?;
+
+    for incoming in listener.incoming() {
+        let stream = match incoming {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept seed exchange connection: {}", e);
+                continue;
+            }
+        };
+
+        let slot = match SeedExchangeSlot::try_acquire() {
+            Some(slot) => slot,
+            None => {
+                warn!(
+                    "Seed exchange connection cap ({}) reached, dropping connection",
+                    MAX_CONCURRENT_SEED_EXCHANGE_CONNECTIONS
+                );
+                continue;
+            }
+        };
+
+        // Handle each connection on its own thread: one slow or stuck peer must not hold up
+        // every other node's seed exchange on the accept loop.
+        std::thread::spawn(move || {
+            let _slot = slot;
+
+            if let Err(e) = stream.set_read_timeout(Some(SEED_EXCHANGE_CONNECTION_TIMEOUT)) {
+                warn!("Failed to set read timeout on seed exchange connection: {}", e);
+                return;
+            }
+            if let Err(e) = stream.set_write_timeout(Some(SEED_EXCHANGE_CONNECTION_TIMEOUT)) {
+                warn!("Failed to set write timeout on seed exchange connection: {}", e);
+                return;
+            }
+
+            let fd = stream.into_raw_fd();
+            let mut retval = sgx_status_t::SGX_SUCCESS;
+            let status = unsafe { ecall_start_seed_server(eid, &mut retval, fd) };
+
+            if status != sgx_status_t::SGX_SUCCESS || retval != sgx_status_t::SGX_SUCCESS {
+                warn!(
+                    "Attested seed exchange session failed: status {:?}, retval {:?}",
+                    status, retval
+                );
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Dials `addr` and pulls the consensus seed over an attested TLS channel, verifying the
+/// listener's embedded quote before the enclave accepts the seed it hands back.
+pub fn join_via_attested_tls(eid: sgx_enclave_id_t, addr: &str) -> SgxResult<[u8; ENCRYPTED_SEED_SIZE]> {
+    info!("Joining the network via attested TLS at {}", addr);
+
+    let stream = TcpStream::connect(addr).map_err(|e| {
+        error!("Failed to connect to seed exchange listener at {}: {}", addr, e);
+        sgx_status_t::SGX_ERROR_UNEXPECTED
+    })?;
+
+    let fd = stream.into_raw_fd();
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let mut seed = [0u8; ENCRYPTED_SEED_SIZE];
+    let status = unsafe { ecall_join_via_seed_client(eid, &mut retval, fd, &mut seed) };
+
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+
+    if retval != sgx_status_t::SGX_SUCCESS {
+        return Err(retval);
+    }
+
+    Ok(seed)
+}
+
+#[cfg(test)]
+mod host_port_tests {
+    use super::split_host_port;
+
+    #[test]
+    fn splits_host_and_port() {
+        assert_eq!(
+            split_host_port("example.com:443", 80),
+            ("example.com".to_string(), 443)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_default_port_with_no_colon() {
+        assert_eq!(
+            split_host_port("example.com", 80),
+            ("example.com".to_string(), 80)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_default_port_on_unparseable_port() {
+        // A malformed port (typo, or out of u16 range) must not drag the whole string,
+        // including the otherwise-valid host, down with it.
+        assert_eq!(
+            split_host_port("example.com:99999999", 80),
+            ("example.com".to_string(), 80)
+        );
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::attestation::retry_quote;
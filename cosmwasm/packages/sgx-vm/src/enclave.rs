@@ -1,7 +1,7 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{env, path::Path};
 
-use enclave_ffi_types::RuntimeConfiguration;
+use enclave_ffi_types::{AttestationMode, RuntimeConfiguration};
 use sgx_types::{
     sgx_attributes_t, sgx_enclave_id_t, sgx_launch_token_t, sgx_misc_attribute_t, sgx_status_t,
     SgxResult,
@@ -92,12 +92,37 @@ extern "C" {
 
 pub struct EnclaveRuntimeConfig {
     pub module_cache_size: u8,
+    /// Which attestation flow the enclave should use when producing a quote for registration:
+    /// the legacy EPID/IAS path, or DCAP/ECDSA for hardware where EPID has been deprecated.
+    pub attestation_mode: AttestationMode,
+    /// Overrides the `SCRT_ATTESTATION_HOST` env var read by the attestation ocalls, letting
+    /// operators point at a local PCCS/caching proxy without touching the process environment.
+    pub attestation_endpoint: Option<String>,
+    /// Whether the enclave should maintain a monotonic counter alongside the sealed seed and
+    /// refuse to start if the sealed value is behind the hardware counter. Disable this on
+    /// hardware whose platform software doesn't support monotonic counters.
+    ///
+    /// NOTE: this only threads the toggle through to `RuntimeConfiguration`; the trusted-side
+    /// counter/seal logic it's meant to enable isn't implemented in this tree yet, so setting
+    /// this to `true` does not currently provide rollback protection. See the NOTE right after
+    /// `ocall_sgx_init_quote` in cosmwasm/lib/sgx-vm/src/attestation.rs.
+    pub configure_rollback_protection: bool,
+    /// Total number of TCS slots to budget across the transaction and query pools.
+    pub tcs_total: u8,
+    /// Of `tcs_total`, the number of slots reserved exclusively for consensus-critical
+    /// transaction execution. The remainder forms the query pool, which transactions may
+    /// additionally borrow from when it's idle (but never the other way around).
+    pub tcs_tx_reserved: u8,
 }
 
 impl EnclaveRuntimeConfig {
     fn to_ffi_type(&self) -> RuntimeConfiguration {
         RuntimeConfiguration {
             module_cache_size: self.module_cache_size,
+            attestation_mode: self.attestation_mode,
+            configure_rollback_protection: self.configure_rollback_protection,
+            tcs_total: self.tcs_total,
+            tcs_tx_reserved: self.tcs_tx_reserved,
         }
     }
 }
@@ -110,6 +135,17 @@ pub fn configure_enclave(config: EnclaveRuntimeConfig) -> SgxResult<()> {
     *configured = true;
     drop(configured);
 
+    if let Some(endpoint) = &config.attestation_endpoint {
+        env::set_var("SCRT_ATTESTATION_HOST", endpoint);
+    }
+
+    // Clamp a misconfigured tcs_tx_reserved (e.g. a typo'd value above tcs_total) so the two
+    // pools together never hold more than tcs_total slots. tcs_total itself is taken as given
+    // and is not checked against the enclave's compiled TCS count.
+    let tx_pool_size = config.tcs_tx_reserved.min(config.tcs_total);
+    let query_pool_size = config.tcs_total - tx_pool_size;
+    QUERY_DOORBELL.reconfigure(tx_pool_size, query_pool_size);
+
     let enclave = get_enclave()?;
 
     let mut retval = sgx_status_t::SGX_SUCCESS;
@@ -129,81 +165,254 @@ pub fn configure_enclave(config: EnclaveRuntimeConfig) -> SgxResult<()> {
 }
 
 /// This const determines how many seconds we wait when trying to get access to the enclave
-/// before giving up.
-const ENCLAVE_LOCK_TIMEOUT: u64 = 6 * 5;
-const TCS_NUM: u8 = 16;
+/// before giving up, for consensus-critical transaction execution.
+const TX_LOCK_TIMEOUT: u64 = 6 * 5;
+/// Queries back off faster than transactions: they're not consensus-critical, and should fail
+/// fast under contention rather than hold up the caller for as long as a transaction would.
+const QUERY_LOCK_TIMEOUT: u64 = 6 * 2;
+
+/// Default total TCS budget, and default split between the two pools, used until
+/// `configure_enclave` runs with an operator-supplied `EnclaveRuntimeConfig`.
+const DEFAULT_TCS_NUM: u8 = 16;
+const DEFAULT_TCS_TX_RESERVED: u8 = 4;
+
+/// Queries and transactions are accounted separately even though they share one physical TCS
+/// budget; see `Doorbell`.
 lazy_static! {
-    static ref QUERY_DOORBELL: Doorbell = Doorbell::new(TCS_NUM);
+    static ref QUERY_DOORBELL: Doorbell =
+        Doorbell::new(DEFAULT_TCS_TX_RESERVED, DEFAULT_TCS_NUM - DEFAULT_TCS_TX_RESERVED);
+}
+
+/// Recursive queries (a contract querying another contract) are already holding a TCS slot,
+/// so they don't draw from either pool -- but they're still bounded by a per-chain-of-calls
+/// depth limit, so unbounded recursion can't deadlock the enclave some other way.
+const MAX_RECURSIVE_QUERY_DEPTH: u8 = 8;
+
+thread_local! {
+    static RECURSIVE_QUERY_DEPTH: std::cell::Cell<u8> = std::cell::Cell::new(0);
+}
+
+fn enter_recursive_query() -> bool {
+    RECURSIVE_QUERY_DEPTH.with(|depth| {
+        if depth.get() >= MAX_RECURSIVE_QUERY_DEPTH {
+            false
+        } else {
+            depth.set(depth.get() + 1);
+            true
+        }
+    })
+}
+
+fn exit_recursive_query() {
+    RECURSIVE_QUERY_DEPTH.with(|depth| depth.set(depth.get() - 1));
+}
+
+/// Which of the two pools a caller is entering the enclave for. Transactions and queries are
+/// given different timeouts/backoff via `get_query_token`, and transactions are additionally
+/// allowed to borrow idle query slots (queries may never borrow from the transaction pool).
+#[derive(Clone, Copy)]
+pub enum TokenPriority {
+    Transaction,
+    Query,
+}
+
+/// Where a granted token came from: a recursive entry (released by decrementing the depth
+/// counter) or one of the two pools, so `Drop` can return the slot to the right counter.
+#[derive(Clone, Copy)]
+enum TokenSource {
+    Recursive,
+    TxPool,
+    QueryPool,
+}
+
+struct PoolCounts {
+    /// Free slots reserved exclusively for transactions.
+    tx_free: u8,
+    /// Free slots in the query pool; also the pool transactions borrow from once `tx_free`
+    /// is exhausted.
+    query_free: u8,
 }
 
 struct Doorbell {
     condvar: Condvar,
-    /// Amount of tasks allowed to use the enclave at the same time.
-    count: Mutex<u8>,
+    counts: Mutex<PoolCounts>,
 }
 
 impl Doorbell {
-    fn new(count: u8) -> Self {
+    fn new(tx_pool_size: u8, query_pool_size: u8) -> Self {
         Self {
             condvar: Condvar::new(),
-            count: Mutex::new(count),
+            counts: Mutex::new(PoolCounts {
+                tx_free: tx_pool_size,
+                query_free: query_pool_size,
+            }),
         }
     }
 
-    fn wait_for(&'static self, duration: Duration, recursive: bool) -> Option<EnclaveQueryToken> {
-        // eprintln!("Query Token creation. recursive: {}", recursive);
-        if !recursive {
-            let mut count = self.count.lock();
-            // eprintln!(
-            //     "The current count of tasks is {}/{}, attempting to increase.",
-            //     TCS_NUM - *count,
-            //     TCS_NUM
-            // );
-            if *count == 0 {
-                // try to wait for other tasks to complete
-                let wait = self.condvar.wait_for(&mut count, duration);
-                // double check that the count is nonzero, so there's an available slot in the enclave.
-                if wait.timed_out() || *count == 0 {
-                    return None;
+    /// Resizes the two pools. Meant to be called once, from `configure_enclave`, before the
+    /// doorbell sees meaningful traffic.
+    fn reconfigure(&self, tx_pool_size: u8, query_pool_size: u8) {
+        let mut counts = self.counts.lock();
+        counts.tx_free = tx_pool_size;
+        counts.query_free = query_pool_size;
+    }
+
+    fn try_acquire(counts: &mut PoolCounts, priority: TokenPriority) -> Option<TokenSource> {
+        match priority {
+            TokenPriority::Transaction => {
+                if counts.tx_free > 0 {
+                    counts.tx_free -= 1;
+                    Some(TokenSource::TxPool)
+                } else if counts.query_free > 0 {
+                    counts.query_free -= 1;
+                    Some(TokenSource::QueryPool)
+                } else {
+                    None
+                }
+            }
+            TokenPriority::Query => {
+                if counts.query_free > 0 {
+                    counts.query_free -= 1;
+                    Some(TokenSource::QueryPool)
+                } else {
+                    None
                 }
             }
-            *count -= 1;
         }
-        Some(EnclaveQueryToken::new(self, recursive))
+    }
+
+    fn wait_for(&'static self, duration: Duration, priority: TokenPriority) -> Option<EnclaveQueryToken> {
+        let mut counts = self.counts.lock();
+
+        if let Some(source) = Self::try_acquire(&mut counts, priority) {
+            return Some(EnclaveQueryToken::new(self, source));
+        }
+
+        // The condvar is shared between both pools, so a wakeup doesn't necessarily mean a
+        // slot freed up for our priority class (e.g. a tx-pool release wakes a query waiter).
+        // Keep waiting out the rest of `duration` rather than giving up on the first
+        // non-matching wakeup.
+        let deadline = Instant::now() + duration;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+
+            let wait = self.condvar.wait_for(&mut counts, remaining);
+
+            if let Some(source) = Self::try_acquire(&mut counts, priority) {
+                return Some(EnclaveQueryToken::new(self, source));
+            }
+
+            if wait.timed_out() {
+                return None;
+            }
+        }
     }
 }
 
 pub struct EnclaveQueryToken {
     doorbell: &'static Doorbell,
-    recursive: bool,
+    source: TokenSource,
 }
 
 impl EnclaveQueryToken {
-    fn new(doorbell: &'static Doorbell, recursive: bool) -> Self {
-        Self {
-            doorbell,
-            recursive,
-        }
+    fn new(doorbell: &'static Doorbell, source: TokenSource) -> Self {
+        Self { doorbell, source }
     }
 }
 
 impl Drop for EnclaveQueryToken {
     fn drop(&mut self) {
-        // eprintln!("Query Token destruction. recursive: {}", self.recursive);
-        if !self.recursive {
-            let mut count = self.doorbell.count.lock();
-            // eprintln!(
-            //     "The current count of tasks is {}/{}, attempting to decrease.",
-            //     TCS_NUM - *count,
-            //     TCS_NUM
-            // );
-            *count += 1;
-            drop(count);
-            self.doorbell.condvar.notify_one();
+        match self.source {
+            TokenSource::Recursive => exit_recursive_query(),
+            TokenSource::TxPool => {
+                let mut counts = self.doorbell.counts.lock();
+                counts.tx_free += 1;
+                drop(counts);
+                self.doorbell.condvar.notify_one();
+            }
+            TokenSource::QueryPool => {
+                let mut counts = self.doorbell.counts.lock();
+                counts.query_free += 1;
+                drop(counts);
+                self.doorbell.condvar.notify_one();
+            }
         }
     }
 }
 
-pub fn get_query_token(recursive: bool) -> Option<EnclaveQueryToken> {
-    QUERY_DOORBELL.wait_for(Duration::from_secs(ENCLAVE_LOCK_TIMEOUT), recursive)
+pub fn get_query_token(priority: TokenPriority, recursive: bool) -> Option<EnclaveQueryToken> {
+    if recursive {
+        return if enter_recursive_query() {
+            Some(EnclaveQueryToken::new(&QUERY_DOORBELL, TokenSource::Recursive))
+        } else {
+            None
+        };
+    }
+
+    let timeout = match priority {
+        TokenPriority::Transaction => Duration::from_secs(TX_LOCK_TIMEOUT),
+        TokenPriority::Query => Duration::from_secs(QUERY_LOCK_TIMEOUT),
+    };
+
+    QUERY_DOORBELL.wait_for(timeout, priority)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Doorbell, PoolCounts, TokenPriority, TokenSource};
+
+    #[test]
+    fn transaction_prefers_its_own_reserved_pool() {
+        let mut counts = PoolCounts {
+            tx_free: 2,
+            query_free: 2,
+        };
+
+        let source = Doorbell::try_acquire(&mut counts, TokenPriority::Transaction).unwrap();
+
+        assert!(matches!(source, TokenSource::TxPool));
+        assert_eq!(counts.tx_free, 1);
+        assert_eq!(counts.query_free, 2);
+    }
+
+    #[test]
+    fn transaction_borrows_an_idle_query_slot_once_its_pool_is_empty() {
+        let mut counts = PoolCounts {
+            tx_free: 0,
+            query_free: 2,
+        };
+
+        let source = Doorbell::try_acquire(&mut counts, TokenPriority::Transaction).unwrap();
+
+        assert!(matches!(source, TokenSource::QueryPool));
+        assert_eq!(counts.tx_free, 0);
+        assert_eq!(counts.query_free, 1);
+    }
+
+    #[test]
+    fn query_never_borrows_the_transaction_pool() {
+        let mut counts = PoolCounts {
+            tx_free: 2,
+            query_free: 0,
+        };
+
+        assert!(Doorbell::try_acquire(&mut counts, TokenPriority::Query).is_none());
+        // Untouched: a failed acquire must not consume a slot from either pool.
+        assert_eq!(counts.tx_free, 2);
+        assert_eq!(counts.query_free, 0);
+    }
+
+    #[test]
+    fn exhausted_pools_refuse_both_priorities() {
+        let mut counts = PoolCounts {
+            tx_free: 0,
+            query_free: 0,
+        };
+
+        assert!(Doorbell::try_acquire(&mut counts, TokenPriority::Transaction).is_none());
+        assert!(Doorbell::try_acquire(&mut counts, TokenPriority::Query).is_none());
+    }
 }